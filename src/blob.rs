@@ -0,0 +1,182 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # The blob column
+//! A second, content-addressed store. Identical payloads - common for repeated
+//! scripts or witness data - are written once and referenced by many keys. The
+//! main key-value record holds an `Offset` into this column instead of inlining
+//! the data; each blob keeps a reference count and `prune` returns the space of
+//! blobs that reached zero to a free list for reuse.
+
+use types::Offset;
+
+use std::collections::HashMap;
+
+/// a stored blob with its content hash and live reference count
+struct Blob {
+    hash: Vec<u8>,
+    data: Vec<u8>,
+    size: u64,
+    refcount: u64,
+}
+
+/// a freed region available to the allocator
+struct Free {
+    offset: Offset,
+    size: u64,
+}
+
+/// content-addressed, reference-counted store of large payloads
+pub struct BlobColumn {
+    blobs: HashMap<u64, Blob>,
+    by_hash: HashMap<Vec<u8>, Offset>,
+    keys: HashMap<Vec<u8>, Offset>,
+    tip: u64,
+    free: Vec<Free>,
+}
+
+impl BlobColumn {
+    /// a fresh, empty blob column
+    pub fn new () -> BlobColumn {
+        BlobColumn {
+            blobs: HashMap::new(),
+            by_hash: HashMap::new(),
+            keys: HashMap::new(),
+            tip: 0,
+            free: Vec::new(),
+        }
+    }
+
+    /// store `data` under `key`, deduplicating on `blob_hash`. An identical payload
+    /// already present is shared: its reference count is bumped rather than writing
+    /// the bytes again. Returns the blob offset the key-value record should store.
+    pub fn put_referenced (&mut self, key: &[u8], blob_hash: &[u8], data: &[u8]) -> Offset {
+        if let Some(&offset) = self.by_hash.get(blob_hash) {
+            if let Some(blob) = self.blobs.get_mut(&offset.as_u64()) {
+                blob.refcount += 1;
+            }
+            self.keys.insert(key.to_vec(), offset);
+            return offset;
+        }
+        let size = data.len() as u64;
+        let offset = self.allocate(size);
+        self.blobs.insert(offset.as_u64(), Blob {
+            hash: blob_hash.to_vec(),
+            data: data.to_vec(),
+            size,
+            refcount: 1,
+        });
+        self.by_hash.insert(blob_hash.to_vec(), offset);
+        self.keys.insert(key.to_vec(), offset);
+        offset
+    }
+
+    /// the blob offset a key resolves to, if any
+    pub fn offset_of (&self, key: &[u8]) -> Option<Offset> {
+        self.keys.get(key).cloned()
+    }
+
+    /// the payload referenced by `key`
+    pub fn get (&self, key: &[u8]) -> Option<&[u8]> {
+        self.keys.get(key)
+            .and_then(|offset| self.blobs.get(&offset.as_u64()))
+            .map(|blob| blob.data.as_slice())
+    }
+
+    /// drop one reference from the blob `key` points to. The blob survives until
+    /// `prune` reclaims it, so a re-`put` before then costs nothing.
+    pub fn release (&mut self, key: &[u8]) {
+        if let Some(offset) = self.keys.remove(key) {
+            if let Some(blob) = self.blobs.get_mut(&offset.as_u64()) {
+                if blob.refcount > 0 {
+                    blob.refcount -= 1;
+                }
+            }
+        }
+    }
+
+    /// free every blob whose reference count reached zero, returning their space to
+    /// the free list, and report how many were reclaimed
+    pub fn prune (&mut self) -> usize {
+        let dead: Vec<u64> = self.blobs.iter()
+            .filter(|&(_, blob)| blob.refcount == 0)
+            .map(|(&offset, _)| offset)
+            .collect();
+        for offset in &dead {
+            if let Some(blob) = self.blobs.remove(offset) {
+                self.by_hash.remove(&blob.hash);
+                self.free.push(Free { offset: Offset::from(*offset), size: blob.size });
+            }
+        }
+        dead.len()
+    }
+
+    /// hand out space for a blob of `size` bytes, reusing a freed region that is
+    /// large enough before extending the column. Only `put_referenced` draws on it;
+    /// a reused region is handed out whole, and any space beyond `size` stays unused.
+    fn allocate (&mut self, size: u64) -> Offset {
+        if let Some(i) = self.free.iter().position(|f| f.size >= size) {
+            return self.free.remove(i).offset;
+        }
+        let offset = Offset::from(self.tip);
+        self.tip += size;
+        offset
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn identical_payloads_are_shared () {
+        let mut column = BlobColumn::new();
+        let data = vec![0x42u8; 1024];
+        let first = column.put_referenced(b"key-a", b"hash-1", &data);
+        let second = column.put_referenced(b"key-b", b"hash-1", &data);
+        // both keys resolve to the same single blob
+        assert_eq!(first, second);
+        assert_eq!(column.get(b"key-a"), Some(data.as_slice()));
+        assert_eq!(column.get(b"key-b"), Some(data.as_slice()));
+    }
+
+    #[test]
+    fn prune_frees_only_unreferenced_blobs () {
+        let mut column = BlobColumn::new();
+        let data = vec![1u8; 16];
+        column.put_referenced(b"key-a", b"hash-1", &data);
+        column.put_referenced(b"key-b", b"hash-1", &data);
+        // one reference remains after releasing a single key
+        column.release(b"key-a");
+        assert_eq!(column.prune(), 0);
+        assert_eq!(column.get(b"key-b"), Some(data.as_slice()));
+        // releasing the last reference makes the blob collectable
+        column.release(b"key-b");
+        assert_eq!(column.prune(), 1);
+        assert_eq!(column.get(b"key-b"), None);
+    }
+
+    #[test]
+    fn freed_space_is_reused () {
+        let mut column = BlobColumn::new();
+        let first = column.put_referenced(b"key-a", b"hash-1", &vec![1u8; 32]);
+        column.release(b"key-a");
+        assert_eq!(column.prune(), 1);
+        // a new blob of equal size reuses the freed region
+        let second = column.put_referenced(b"key-b", b"hash-2", &vec![2u8; 32]);
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,472 @@
+//
+// Copyright 2018 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # The data file
+//! Append only storage of key-value records. A record frames its body with a
+//! leading varint length; the body carries the record type, the next-in-bucket
+//! link, the key and the stored value. Offsets are absolute positions of a
+//! record's framing length within the file.
+
+use types::{Offset, OffsetReader, U24, U48, read_u24, read_u48, read_varint, write_varint, CodecError};
+use types::{Tlv, write_tlv, read_tlv, crc32, TLV_CRC32, MAX_CHUNK_SIZE};
+
+use std::io::Cursor;
+
+/// a plain key-value record holding its value inline
+const REC_DATA: u8 = 0;
+/// the first record of a value spanned across several records
+const REC_SPAN_HEAD: u8 = 1;
+/// a continuation record holding one chunk of a spanned value
+const REC_SPAN_CONT: u8 = 2;
+/// a record whose value is kept once in the blob column and referenced by offset
+const REC_BLOB_REF: u8 = 3;
+
+/// TLV trailer types this reader understands; unknown even types are rejected,
+/// unknown odd types skipped
+const KNOWN_TLV: [u64; 1] = [TLV_CRC32];
+
+/// append only data file
+pub struct DataFile {
+    content: Vec<u8>,
+}
+
+/// a decoded record body
+pub(crate) enum Body {
+    /// inline key-value record
+    Data { key: Vec<u8>, data: Vec<u8>, link: Offset },
+    /// head of a spanned value: the total length and the first chunk
+    SpanHead { key: Vec<u8>, total: usize, next_span: Offset, chunk: Vec<u8>, link: Offset },
+    /// a spanned-value continuation chunk
+    SpanCont { next_span: Offset, chunk: Vec<u8> },
+    /// a key whose value lives in the blob column, held here as an offset
+    BlobRef { key: Vec<u8>, blob: Offset, link: Offset },
+}
+
+/// a decoded record together with the offset of the record that follows it
+pub(crate) struct Decoded {
+    pub body: Body,
+    pub next: Offset,
+}
+
+/// read exactly `n` bytes without running past the record boundary `end`
+fn read_bytes (cursor: &mut Cursor<Vec<u8>>, end: u64, n: usize) -> Result<Vec<u8>, CodecError> {
+    use std::io::Read;
+    if (n as u64) > end.saturating_sub(cursor.position()) {
+        return Err(CodecError::Oversized);
+    }
+    let mut buf = vec![0u8; n];
+    cursor.read_exact(&mut buf).map_err(|_| CodecError::Truncated)?;
+    Ok(buf)
+}
+
+/// read a length-prefixed key
+fn read_key (cursor: &mut Cursor<Vec<u8>>, end: u64) -> Result<Vec<u8>, CodecError> {
+    let key_len = read_varint(cursor)? as usize;
+    read_bytes(cursor, end, key_len)
+}
+
+/// read a `U24` length-prefixed chunk
+fn read_chunk (cursor: &mut Cursor<Vec<u8>>, end: u64) -> Result<Vec<u8>, CodecError> {
+    let len = read_u24(cursor, end)?.as_usize();
+    read_bytes(cursor, end, len)
+}
+
+/// append a `U24` length-prefixed chunk
+fn write_chunk (body: &mut Vec<u8>, data: &[u8]) {
+    let mut len = [0u8; 3];
+    U24::from(data.len()).serialize(&mut len);
+    body.extend_from_slice(&len);
+    body.extend_from_slice(data);
+}
+
+/// big-endian bytes of a checksum
+fn be32 (v: u32) -> Vec<u8> {
+    vec![(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+}
+
+/// the six byte on-disk form of an offset into the blob column
+fn u48_bytes (offset: Offset) -> Vec<u8> {
+    let mut buf = [0u8; 6];
+    U48::from(offset.as_u64() as usize).serialize(&mut buf);
+    buf.to_vec()
+}
+
+impl DataFile {
+    /// a fresh, empty data file
+    pub fn new () -> DataFile {
+        DataFile { content: Vec::new() }
+    }
+
+    /// offset one past the last stored record
+    pub fn tip (&self) -> Offset {
+        Offset::from(self.content.len() as u64)
+    }
+
+    /// append a key-value record carrying `link` as its next-in-bucket pointer and
+    /// return the offset of the new record. The link is stored with `Offset::encode_link`,
+    /// relative to this record when that is shorter than the absolute form, so a nearby
+    /// pointer costs only a byte or two.
+    pub fn append (&mut self, key: &[u8], data: &[u8], link: Offset) -> Offset {
+        self.append_full(key, data, link, &[])
+    }
+
+    /// append a record followed by a TLV trailer. The trailer carries optional
+    /// per-record metadata and must already be in strictly ascending type order.
+    /// A value larger than a single `U24` chunk is split across linked records.
+    pub fn append_full (&mut self, key: &[u8], data: &[u8], link: Offset, trailer: &[Tlv]) -> Offset {
+        if data.len() > MAX_CHUNK_SIZE {
+            return self.append_spanned(key, data, link, trailer);
+        }
+        let offset = self.tip();
+        let mut body = Vec::new();
+        body.push(REC_DATA);
+        body.extend_from_slice(&link.encode_link(offset));
+        write_varint(key.len() as u64, &mut body);
+        body.extend_from_slice(key);
+        write_chunk(&mut body, data);
+        self.append_body(body, trailer)
+    }
+
+    /// append a record that points at a value stored in the blob column instead of
+    /// inlining it, returning the new record offset. Deduplicated payloads are written
+    /// once in the blob column and many keys reference them through records like this.
+    pub fn append_referenced (&mut self, key: &[u8], blob: Offset, link: Offset) -> Offset {
+        let offset = self.tip();
+        let mut body = Vec::new();
+        body.push(REC_BLOB_REF);
+        body.extend_from_slice(&link.encode_link(offset));
+        write_varint(key.len() as u64, &mut body);
+        body.extend_from_slice(key);
+        body.extend_from_slice(&blob.to_varint());
+        self.append_body(body, &[])
+    }
+
+    /// split an oversized value across a head record and a chain of continuation
+    /// records, returning the head offset. Continuations are written first so each
+    /// predecessor can point at the one that follows it.
+    fn append_spanned (&mut self, key: &[u8], data: &[u8], link: Offset, trailer: &[Tlv]) -> Offset {
+        let chunks: Vec<&[u8]> = data.chunks(MAX_CHUNK_SIZE).collect();
+        let mut next = Offset::default();
+        for i in (1 .. chunks.len()).rev() {
+            let mut body = Vec::new();
+            body.push(REC_SPAN_CONT);
+            body.extend_from_slice(&next.to_varint());
+            write_chunk(&mut body, chunks[i]);
+            next = self.append_body(body, &[]);
+        }
+        let offset = self.tip();
+        let mut body = Vec::new();
+        body.push(REC_SPAN_HEAD);
+        body.extend_from_slice(&link.encode_link(offset));
+        write_varint(key.len() as u64, &mut body);
+        body.extend_from_slice(key);
+        let mut total = [0u8; 6];
+        U48::from(data.len()).serialize(&mut total);
+        body.extend_from_slice(&total);
+        body.extend_from_slice(&next.to_varint());
+        write_chunk(&mut body, chunks[0]);
+        self.append_body(body, trailer)
+    }
+
+    /// checksum `body`, append the CRC32 (and any caller trailer) as a TLV trailer,
+    /// frame the whole thing with its length and store it; return the record offset
+    fn append_body (&mut self, mut body: Vec<u8>, trailer: &[Tlv]) -> Offset {
+        let offset = self.tip();
+        let crc = crc32(&body);
+        let mut records = Vec::with_capacity(1 + trailer.len());
+        records.push(Tlv { typ: TLV_CRC32, value: be32(crc) });
+        for t in trailer {
+            records.push(Tlv { typ: t.typ, value: t.value.clone() });
+        }
+        write_tlv(&records, &mut body);
+        write_varint(body.len() as u64, &mut self.content);
+        self.content.extend_from_slice(&body);
+        offset
+    }
+
+    /// decode the record at `offset`, validating its checksum
+    pub(crate) fn decode (&self, offset: Offset) -> Result<Decoded, CodecError> {
+        let mut cursor = Cursor::new(self.content.clone());
+        cursor.set_position(offset.as_u64());
+        let body_len = read_varint(&mut cursor)?;
+        let body_start = cursor.position();
+        let end = body_start + body_len;
+        let rec_type = read_bytes(&mut cursor, end, 1)?[0];
+        let body = match rec_type {
+            REC_DATA => {
+                let link = cursor.read_link(offset)?;
+                let key = read_key(&mut cursor, end)?;
+                let data = read_chunk(&mut cursor, end)?;
+                Body::Data { key, data, link }
+            }
+            REC_SPAN_HEAD => {
+                let link = cursor.read_link(offset)?;
+                let key = read_key(&mut cursor, end)?;
+                let total = read_u48(&mut cursor, end)?.as_usize();
+                let next_span = cursor.read_varint_offset()?;
+                let chunk = read_chunk(&mut cursor, end)?;
+                Body::SpanHead { key, total, next_span, chunk, link }
+            }
+            REC_SPAN_CONT => {
+                let next_span = cursor.read_varint_offset()?;
+                let chunk = read_chunk(&mut cursor, end)?;
+                Body::SpanCont { next_span, chunk }
+            }
+            REC_BLOB_REF => {
+                let link = cursor.read_link(offset)?;
+                let key = read_key(&mut cursor, end)?;
+                let blob = cursor.read_varint_offset()?;
+                Body::BlobRef { key, blob, link }
+            }
+            _ => return Err(CodecError::Oversized)
+        };
+        // everything from the record type through the payload is checksummed
+        let checksummed = self.content[body_start as usize .. cursor.position() as usize].to_vec();
+        let trailer = read_tlv(&mut cursor, end, &KNOWN_TLV)?;
+        for t in &trailer {
+            if t.typ == TLV_CRC32 && t.value.len() == 4 {
+                let stored = ((t.value[0] as u32) << 24) | ((t.value[1] as u32) << 16)
+                    | ((t.value[2] as u32) << 8) | t.value[3] as u32;
+                if stored != crc32(&checksummed) {
+                    return Err(CodecError::ChecksumMismatch);
+                }
+            }
+        }
+        Ok(Decoded { body, next: Offset::from(end) })
+    }
+
+    /// decode the record at `offset`, returning its key, value and next-in-bucket link.
+    /// A spanned value is reassembled transparently by following its chunk chain.
+    pub fn get (&self, offset: Offset) -> Result<(Vec<u8>, Vec<u8>, Offset), CodecError> {
+        match self.decode(offset)?.body {
+            Body::Data { key, data, link } => Ok((key, data, link)),
+            Body::SpanHead { key, total, next_span, chunk, link } => {
+                let mut data = chunk;
+                let mut ptr = next_span;
+                while data.len() < total {
+                    match self.decode(ptr)?.body {
+                        Body::SpanCont { next_span, chunk } => {
+                            data.extend_from_slice(&chunk);
+                            ptr = next_span;
+                        }
+                        _ => return Err(CodecError::Oversized)
+                    }
+                }
+                if data.len() != total {
+                    return Err(CodecError::Oversized);
+                }
+                Ok((key, data, link))
+            }
+            // a reference record resolves to the blob offset; the caller reads the
+            // payload from the blob column, which owns the bytes
+            Body::BlobRef { key, blob, link } => Ok((key, u48_bytes(blob), link)),
+            Body::SpanCont { .. } => Err(CodecError::Oversized)
+        }
+    }
+
+    /// walk every record from the first to the append tip, validating each
+    /// checksum and that the stored links stay within the file. Returns the
+    /// offset of the first inconsistent record, or `None` if the file is intact.
+    pub fn verify (&self) -> Option<Offset> {
+        let tip = self.tip().as_u64();
+        let mut pos = Offset::default();
+        while pos.as_u64() < tip {
+            match self.decode(pos) {
+                Ok(decoded) => {
+                    let within = match decoded.body {
+                        Body::Data { link, .. } => link.as_u64() <= tip,
+                        Body::SpanHead { link, next_span, .. } =>
+                            link.as_u64() <= tip && next_span.as_u64() <= tip,
+                        Body::SpanCont { next_span, .. } => next_span.as_u64() <= tip,
+                        Body::BlobRef { link, .. } => link.as_u64() <= tip
+                    };
+                    if !within {
+                        return Some(pos);
+                    }
+                    pos = decoded.next;
+                }
+                Err(_) => return Some(pos)
+            }
+        }
+        None
+    }
+
+    /// iterate every stored value from the first record to the append tip
+    pub fn iter (&self) -> DataIterator {
+        self.scan_from(Offset::default())
+    }
+
+    /// resume iteration from an absolute record offset, e.g. a checkpoint saved
+    /// while rebuilding a dropped index or migrating the database
+    pub fn scan_from (&self, offset: Offset) -> DataIterator {
+        DataIterator { file: self, pos: offset }
+    }
+}
+
+/// forward cursor over the data file, yielding each stored `(key, value)` and
+/// transparently reassembling spanned values while skipping their continuation
+/// chunks. Stops at the first record it cannot decode.
+pub struct DataIterator<'a> {
+    file: &'a DataFile,
+    pos: Offset,
+}
+
+impl<'a> Iterator for DataIterator<'a> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next (&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let tip = self.file.tip().as_u64();
+        while self.pos.as_u64() < tip {
+            let decoded = match self.file.decode(self.pos) {
+                Ok(decoded) => decoded,
+                Err(_) => return None
+            };
+            let here = self.pos;
+            self.pos = decoded.next;
+            match decoded.body {
+                Body::Data { key, data, .. } => return Some((key, data)),
+                // a reference record yields its key with the blob column offset
+                Body::BlobRef { key, blob, .. } => return Some((key, u48_bytes(blob))),
+                // reassemble the whole value from the head record
+                Body::SpanHead { .. } => match self.file.get(here) {
+                    Ok((key, data, _)) => return Some((key, data)),
+                    Err(_) => return None
+                },
+                // continuation chunks are internal to a spanned value
+                Body::SpanCont { .. } => continue
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn data_record_round_trip () {
+        let mut df = DataFile::new();
+        let first = df.append(b"alpha", b"one", Offset::default());
+        let second = df.append(b"beta", b"two", first);
+        let (k1, v1, l1) = df.get(first).unwrap();
+        assert_eq!(k1, b"alpha");
+        assert_eq!(v1, b"one");
+        assert_eq!(l1, Offset::default());
+        let (k2, v2, l2) = df.get(second).unwrap();
+        assert_eq!(k2, b"beta");
+        assert_eq!(v2, b"two");
+        // the second record links back to the first
+        assert_eq!(l2, first);
+    }
+
+    #[test]
+    fn record_skips_unknown_odd_trailer () {
+        // an ignorable (odd type) trailer must not disturb decoding of the value
+        let mut df = DataFile::new();
+        let offset = df.append_full(b"key", b"value", Offset::default(),
+            &[Tlv { typ: 3, value: vec![0xde, 0xad] }]);
+        let (k, v, _) = df.get(offset).unwrap();
+        assert_eq!(k, b"key");
+        assert_eq!(v, b"value");
+    }
+
+    #[test]
+    fn iterator_walks_all_records () {
+        let mut df = DataFile::new();
+        df.append(b"one", b"1", Offset::default());
+        let big = vec![7u8; 20 * 1024 * 1024];
+        df.append(b"big", &big, Offset::default());
+        df.append(b"two", b"2", Offset::default());
+        let collected: Vec<(Vec<u8>, Vec<u8>)> = df.iter().collect();
+        // three values, the spanned one reassembled, continuation chunks skipped
+        assert_eq!(collected.len(), 3);
+        assert_eq!(collected[0], (b"one".to_vec(), b"1".to_vec()));
+        assert_eq!(collected[1].0, b"big");
+        assert_eq!(collected[1].1.len(), big.len());
+        assert_eq!(collected[2], (b"two".to_vec(), b"2".to_vec()));
+    }
+
+    #[test]
+    fn scan_from_resumes () {
+        let mut df = DataFile::new();
+        df.append(b"a", b"1", Offset::default());
+        let second = df.tip();
+        df.append(b"b", b"2", Offset::default());
+        let collected: Vec<(Vec<u8>, Vec<u8>)> = df.scan_from(second).collect();
+        assert_eq!(collected, vec![(b"b".to_vec(), b"2".to_vec())]);
+    }
+
+    #[test]
+    fn spanned_value_round_trips () {
+        // a value well over the 16 MiB U24 chunk cap must split and reassemble
+        let mut df = DataFile::new();
+        let size = 64 * 1024 * 1024;
+        let mut data = vec![0u8; size];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+        let offset = df.append(b"big", &data, Offset::default());
+        let (key, value, _) = df.get(offset).unwrap();
+        assert_eq!(key, b"big");
+        assert_eq!(value.len(), size);
+        assert_eq!(value, data);
+        assert_eq!(df.verify(), None);
+    }
+
+    #[test]
+    fn verify_localizes_corruption () {
+        let mut df = DataFile::new();
+        let first = df.append(b"k", &[0x11u8; 100], Offset::default());
+        let _second = df.append(b"k2", b"v2", first);
+        // an intact file verifies clean
+        assert_eq!(df.verify(), None);
+        // flip a byte inside the first record's payload
+        df.content[20] ^= 0xff;
+        match df.get(first) {
+            Err(CodecError::ChecksumMismatch) => {}
+            other => panic!("expected checksum mismatch, got {:?}", other)
+        }
+        // verify localizes the first bad record
+        assert_eq!(df.verify(), Some(first));
+    }
+
+    #[test]
+    fn blob_ref_record_round_trips () {
+        // a reference record carries the blob column offset in place of the value
+        let mut df = DataFile::new();
+        let first = df.append(b"k", b"v", Offset::default());
+        let blob = Offset::from(4096);
+        let offset = df.append_referenced(b"txid", blob, first);
+        let (key, value, link) = df.get(offset).unwrap();
+        assert_eq!(key, b"txid");
+        assert_eq!(link, first);
+        let mut expected = [0u8; 6];
+        U48::from(blob.as_u64() as usize).serialize(&mut expected);
+        assert_eq!(value, expected.to_vec());
+        assert_eq!(df.verify(), None);
+    }
+
+    #[test]
+    fn nearby_link_is_smaller_than_fixed_word () {
+        // a link to a neighbouring record must cost fewer than the former six bytes
+        let mut df = DataFile::new();
+        let first = df.append(b"k", b"v", Offset::default());
+        let second_offset = df.tip();
+        let encoded = first.encode_link(second_offset);
+        assert!(encoded.len() < 6, "nearby link should shrink below the 6 byte word");
+    }
+}
@@ -24,6 +24,8 @@ use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 
 use std::io::Cursor;
 use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Default, Debug)]
 /// Pointer to persistent data. Limited to 2^48
@@ -53,16 +55,122 @@ impl<'a> From<&'a [u8]> for Offset {
     }
 }
 
+/// header byte preceding a varint link: absolute offset
+pub const LINK_ABSOLUTE: u8 = 0;
+/// header byte preceding a varint link: signed zigzag delta from the linking record's offset
+pub const LINK_DELTA: u8 = 1;
+
+/// error decoding an on-disk record, used to localize corruption instead of panicking
+#[derive(Debug)]
+pub(crate) enum CodecError {
+    /// the buffer ended in the middle of a value
+    Truncated,
+    /// a varint ran past the 64 bit it can represent (a flipped continuation bit)
+    VarintOverflow,
+    /// a length field pointed past the record boundary
+    Oversized,
+    /// TLV types were not in strictly ascending order
+    NotAscending,
+    /// an unknown even (mandatory) TLV type was encountered
+    UnknownMandatory(u64),
+    /// a record's stored checksum did not match its recomputed value
+    ChecksumMismatch,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecError::Truncated => write!(f, "record ended unexpectedly"),
+            CodecError::VarintOverflow => write!(f, "varint overflows 64 bit"),
+            CodecError::Oversized => write!(f, "length field points past record boundary"),
+            CodecError::NotAscending => write!(f, "TLV types not in ascending order"),
+            CodecError::UnknownMandatory(t) => write!(f, "unknown mandatory TLV type {}", t),
+            CodecError::ChecksumMismatch => write!(f, "record checksum mismatch")
+        }
+    }
+}
+
+impl Error for CodecError {}
+
 /// can read offsets from this
 pub trait OffsetReader {
     /// read offset
     fn read_offset (&mut self) -> Offset;
+    /// read an offset stored in the LEB128 varint form written by `Offset::to_varint`
+    fn read_varint_offset (&mut self) -> Result<Offset, CodecError>;
+    /// read a bucket-link offset written by `Offset::encode_link`, resolving the
+    /// delta form against `base`, the offset of the record holding the link
+    fn read_link (&mut self, base: Offset) -> Result<Offset, CodecError>;
 }
 
 impl OffsetReader for Cursor<Vec<u8>> {
     fn read_offset(&mut self) -> Offset {
         Offset(self.read_u48::<BigEndian>().unwrap())
     }
+
+    fn read_varint_offset(&mut self) -> Result<Offset, CodecError> {
+        Ok(Offset::from(read_varint(self)?))
+    }
+
+    fn read_link(&mut self, base: Offset) -> Result<Offset, CodecError> {
+        let header = self.read_u8().map_err(|_| CodecError::Truncated)?;
+        let body = read_varint(self)?;
+        match header {
+            LINK_DELTA => Ok(Offset::from((base.0 as i64 + unzigzag(body)) as u64)),
+            _ => Ok(Offset::from(body))
+        }
+    }
+}
+
+/// write an unsigned integer as a LEB128-style varint: 7 payload bits per byte,
+/// high bit as continuation, little-endian groups
+pub(crate) fn write_varint (n: u64, into: &mut Vec<u8>) {
+    let mut n = n;
+    loop {
+        let mut byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n != 0 {
+            byte |= 0x80;
+        }
+        into.push(byte);
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+/// read a LEB128-style varint written by `write_varint`. A corrupt stream must not
+/// crash the reader, so a truncated buffer or a value wider than 64 bit (ten payload
+/// groups) is reported rather than panicking or silently wrapping.
+pub(crate) fn read_varint (cursor: &mut Cursor<Vec<u8>>) -> Result<u64, CodecError> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        if shift >= 64 {
+            return Err(CodecError::VarintOverflow);
+        }
+        let byte = cursor.read_u8().map_err(|_| CodecError::Truncated)?;
+        // the tenth group has room for bit 63 only
+        if shift == 63 && byte & 0x7e != 0 {
+            return Err(CodecError::VarintOverflow);
+        }
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    Ok(n)
+}
+
+/// map a signed integer onto an unsigned one so that small magnitudes stay short
+fn zigzag (n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+/// inverse of `zigzag`
+fn unzigzag (n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
 }
 
 impl Offset {
@@ -73,6 +181,35 @@ impl Offset {
         v
     }
 
+    /// serialize as a LEB128-style varint, shrinking the common case of a small
+    /// offset from the fixed 6-byte word to two or three bytes
+    pub fn to_varint(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_varint(self.0, &mut v);
+        v
+    }
+
+    /// encode this offset as a bucket-link stored inside the record at `base`.
+    /// The chain usually points to a nearby record, so keep a base value and
+    /// record a small signed delta: emit a `LINK_DELTA` header plus the zigzag
+    /// varint difference when that is shorter than the absolute varint form,
+    /// otherwise fall back to `LINK_ABSOLUTE`.
+    pub fn encode_link(&self, base: Offset) -> Vec<u8> {
+        let mut absolute = Vec::new();
+        write_varint(self.0, &mut absolute);
+        let mut delta = Vec::new();
+        write_varint(zigzag(self.0 as i64 - base.0 as i64), &mut delta);
+        let mut v = Vec::new();
+        if delta.len() < absolute.len() {
+            v.push(LINK_DELTA);
+            v.extend_from_slice(&delta);
+        } else {
+            v.push(LINK_ABSOLUTE);
+            v.extend_from_slice(&absolute);
+        }
+        v
+    }
+
     /// convert to a number
     pub fn as_u64 (&self) -> u64 {
         return self.0;
@@ -125,3 +262,248 @@ impl U24 {
     }
 }
 
+/// largest payload a single data record can hold, bounded by the `U24` size field
+pub(crate) const MAX_CHUNK_SIZE: usize = 0xffffff;
+
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Default, Debug)]
+/// an unsigned 48 bit integer for the total length of a value spanned across records
+pub(crate) struct U48 (usize);
+
+impl From<usize> for U48 {
+    fn from(n: usize) -> Self {
+        U48(n & 0xffffffffffffusize)
+    }
+}
+
+impl U48 {
+    pub fn as_usize (&self) -> usize {
+        return self.0;
+    }
+
+    pub fn serialize (&self, mut into: &mut [u8]) {
+        into.write_u48::<BigEndian>(self.0 as u64).unwrap();
+    }
+}
+
+/// read a `U24` chunk size from the record, bounded by the record end so a
+/// short or corrupt record is reported rather than panicking
+pub(crate) fn read_u24 (cursor: &mut Cursor<Vec<u8>>, end: u64) -> Result<U24, CodecError> {
+    if end.saturating_sub(cursor.position()) < 3 {
+        return Err(CodecError::Truncated);
+    }
+    Ok(U24::from(cursor.read_u24::<BigEndian>().map_err(|_| CodecError::Truncated)? as usize))
+}
+
+/// read a `U48` total length from the record, bounded by the record end. Unlike a
+/// `From<&[u8]>` this cannot panic on a slice shorter than six bytes.
+pub(crate) fn read_u48 (cursor: &mut Cursor<Vec<u8>>, end: u64) -> Result<U48, CodecError> {
+    if end.saturating_sub(cursor.position()) < 6 {
+        return Err(CodecError::Truncated);
+    }
+    Ok(U48::from(cursor.read_u48::<BigEndian>().map_err(|_| CodecError::Truncated)? as usize))
+}
+
+/// TLV type carrying a CRC32 checksum over a record's key, length, link and payload.
+/// Odd, hence ignorable, so databases written without it still read.
+pub(crate) const TLV_CRC32: u64 = 1;
+
+/// CRC32 (IEEE 802.3, reflected) over `data`, used for per-record integrity checks
+pub(crate) fn crc32 (data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0 .. 8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb88320u32 & mask);
+        }
+    }
+    !crc
+}
+
+/// a single type-length-value record in a TLV trailer
+pub(crate) struct Tlv {
+    /// the type, even means mandatory, odd means ignorable
+    pub typ: u64,
+    /// the value bytes
+    pub value: Vec<u8>,
+}
+
+/// append a TLV trailer to a record, writing each `(type, length, value)` triple
+/// as varints. The records must already be sorted in strictly ascending type order.
+pub(crate) fn write_tlv (records: &[Tlv], into: &mut Vec<u8>) {
+    let mut last: Option<u64> = None;
+    for r in records {
+        debug_assert!(last.map_or(true, |l| r.typ > l), "TLV records must be in ascending type order");
+        last = Some(r.typ);
+        write_varint(r.typ, into);
+        write_varint(r.value.len() as u64, into);
+        into.extend_from_slice(&r.value);
+    }
+}
+
+/// read a TLV trailer up to the record boundary at absolute position `end`.
+/// Types must appear in strictly ascending order; an unknown even type is
+/// mandatory and rejected, while an unknown odd type is skipped. `known`
+/// lists the types this reader understands. Every length is validated against
+/// the remaining bytes before allocating, so corrupt input is reported rather
+/// than triggering an unbounded allocation or a panic past the boundary.
+pub(crate) fn read_tlv (cursor: &mut Cursor<Vec<u8>>, end: u64, known: &[u64]) -> Result<Vec<Tlv>, CodecError> {
+    let mut result = Vec::new();
+    let mut last: Option<u64> = None;
+    while cursor.position() < end {
+        let typ = read_varint(cursor)?;
+        if last.map_or(false, |l| typ <= l) {
+            return Err(CodecError::NotAscending);
+        }
+        last = Some(typ);
+        let len = read_varint(cursor)? as u64;
+        // the value must fit inside the record boundary; anything larger is corruption
+        if len > end.saturating_sub(cursor.position()) {
+            return Err(CodecError::Oversized);
+        }
+        let mut value = vec![0u8; len as usize];
+        for b in value.iter_mut() {
+            *b = cursor.read_u8().map_err(|_| CodecError::Truncated)?;
+        }
+        if known.contains(&typ) {
+            result.push(Tlv { typ, value });
+        } else if typ & 1 == 0 {
+            return Err(CodecError::UnknownMandatory(typ));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn varint_round_trip () {
+        for n in &[0u64, 1, 127, 128, 300, 0xffffffu64, 0xffffffffffffu64, u64::max_value()] {
+            let mut v = Vec::new();
+            write_varint(*n, &mut v);
+            let mut cursor = Cursor::new(v);
+            assert_eq!(read_varint(&mut cursor).unwrap(), *n);
+        }
+    }
+
+    #[test]
+    fn zigzag_round_trip () {
+        for n in &[0i64, 1, -1, 63, -64, 999998, -999998] {
+            assert_eq!(unzigzag(zigzag(*n)), *n);
+        }
+    }
+
+    #[test]
+    fn link_uses_delta_for_nearby_offset () {
+        let base = Offset::from(1_000_000);
+        let target = Offset::from(1_000_005);
+        let encoded = target.encode_link(base);
+        assert_eq!(encoded[0], LINK_DELTA);
+        let mut cursor = Cursor::new(encoded);
+        assert_eq!(cursor.read_link(base).unwrap(), target);
+    }
+
+    #[test]
+    fn link_uses_absolute_when_shorter () {
+        let base = Offset::from(1_000_000);
+        let target = Offset::from(2);
+        let encoded = target.encode_link(base);
+        assert_eq!(encoded[0], LINK_ABSOLUTE);
+        let mut cursor = Cursor::new(encoded);
+        assert_eq!(cursor.read_link(base).unwrap(), target);
+    }
+
+    #[test]
+    fn varint_overflow_is_reported () {
+        // eleven continuation bytes exceed the 64 bit a varint can hold
+        let mut cursor = Cursor::new(vec![0xffu8; 11]);
+        match read_varint(&mut cursor) {
+            Err(CodecError::VarintOverflow) => {}
+            other => panic!("expected overflow, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn varint_truncation_is_reported () {
+        let mut cursor = Cursor::new(vec![0x80u8]);
+        match read_varint(&mut cursor) {
+            Err(CodecError::Truncated) => {}
+            other => panic!("expected truncation, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn u48_masks_and_serializes () {
+        // a value above 2^48 is masked down, like Offset
+        let n = U48::from(0x01_0000_0000_0000usize + 0xab_cdef);
+        assert_eq!(n.as_usize(), 0xab_cdef);
+        // serialize then read back through the bounded cursor path
+        let mut buf = [0u8; 6];
+        U48::from(0xab_cdefusize).serialize(&mut buf);
+        let mut cursor = Cursor::new(buf.to_vec());
+        assert_eq!(read_u48(&mut cursor, 6).unwrap().as_usize(), 0xab_cdef);
+    }
+
+    #[test]
+    fn u48_read_rejects_short_input () {
+        let mut cursor = Cursor::new(vec![0u8; 3]);
+        match read_u48(&mut cursor, 3) {
+            Err(CodecError::Truncated) => {}
+            other => panic!("expected truncation, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn crc32_known_answer () {
+        // canonical CRC32 check value for the string "123456789"
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn tlv_round_trip_and_odd_skip () {
+        let records = [
+            Tlv { typ: 1, value: vec![0xaa, 0xbb] },
+            Tlv { typ: 3, value: vec![0xcc] },
+        ];
+        let mut buf = Vec::new();
+        write_tlv(&records, &mut buf);
+        let end = buf.len() as u64;
+        let mut cursor = Cursor::new(buf);
+        // type 3 is odd and unknown here, so it is skipped, leaving only type 1
+        let read = read_tlv(&mut cursor, end, &[1]).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].typ, 1);
+        assert_eq!(read[0].value, vec![0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn tlv_rejects_unknown_mandatory () {
+        let records = [Tlv { typ: 2, value: vec![0xaa] }];
+        let mut buf = Vec::new();
+        write_tlv(&records, &mut buf);
+        let end = buf.len() as u64;
+        let mut cursor = Cursor::new(buf);
+        match read_tlv(&mut cursor, end, &[]) {
+            Err(CodecError::UnknownMandatory(2)) => {}
+            other => panic!("expected unknown mandatory, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn tlv_stops_at_record_boundary () {
+        // type 1 with a declared length of 100, but the record boundary and the
+        // buffer end only two payload bytes later: the reader must not overrun it
+        let buf = vec![1u8, 100, 0xaa, 0xbb];
+        let end = buf.len() as u64;
+        let mut cursor = Cursor::new(buf);
+        match read_tlv(&mut cursor, end, &[1]) {
+            Err(CodecError::Oversized) => {}
+            other => panic!("expected oversized, got {:?}", other)
+        }
+    }
+
+}
+